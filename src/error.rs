@@ -0,0 +1,69 @@
+use std::fmt::{self, Display};
+use std::io;
+use std::path::Path;
+
+/// A simple string-backed error used throughout fcp.
+///
+/// Low-level failures (most commonly [`io::Error`]) are converted into an `Error` so that
+/// callers can format and print a single, consistent error type regardless of where a failure
+/// originated.
+#[derive(Debug)]
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    pub fn new(message: impl Into<String>) -> Self {
+        Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::new(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Extends [`io::Result`] with a way to attach the operation and path(s) involved in a failed
+/// syscall, so the message printed to the user pinpoints exactly what went wrong instead of a
+/// lone "Permission denied".
+pub trait IoResultExt<T> {
+    /// Annotates a failed result with the `operation` that was attempted and its `path`,
+    /// producing e.g. "couldn't open file; path=/a/b; (Permission denied (os error 13))".
+    fn with_context(self, operation: &str, path: &Path) -> Result<T>;
+
+    /// Like [`with_context`](Self::with_context), for operations involving a second path (e.g.
+    /// copying `path` to `other_path`).
+    fn with_context2(self, operation: &str, path: &Path, other_path: &Path) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn with_context(self, operation: &str, path: &Path) -> Result<T> {
+        self.map_err(|err| {
+            Error::new(format!(
+                "couldn't {operation}; path={}; ({err})",
+                path.display()
+            ))
+        })
+    }
+
+    fn with_context2(self, operation: &str, path: &Path, other_path: &Path) -> Result<T> {
+        self.map_err(|err| {
+            Error::new(format!(
+                "couldn't {operation}; path={}; other_path={}; ({err})",
+                path.display(),
+                other_path.display()
+            ))
+        })
+    }
+}
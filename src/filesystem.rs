@@ -0,0 +1,426 @@
+use memmap2::MmapOptions;
+use rayon::prelude::{IndexedParallelIterator, ParallelIterator, ParallelSlice, ParallelSliceMut};
+use sha2::{Digest, Sha256};
+use std::ffi::{CStr, CString};
+use std::fs::{self, DirEntry, File, Metadata, OpenOptions, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use crate::error::{Error, IoResultExt, Result};
+
+// `linux/magic.h`'s `NFS_SUPER_MAGIC`. mmap is unsound over NFS: if another client truncates or
+// otherwise changes the file while it's mapped, the mapping process gets killed with SIGBUS.
+const NFS_SUPER_MAGIC: i64 = 0x6969;
+
+// Below this size the overhead of setting up two mappings isn't worth it over a plain
+// `fs::copy`, which is already well-optimized (it uses `copy_file_range` on Linux).
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+// Large mappings are split into chunks of this size so they can be copied across multiple
+// threads via rayon, rather than `memcpy`-ing gigabytes on a single core.
+const MMAP_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+// Buffer size used by `copy_with_hash` and `hash_file` to stream files through SHA-256 without
+// loading them into memory in full.
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The kind of filesystem object a path refers to, as reported by `stat`/`lstat`.
+///
+/// fcp dispatches on this rather than `std::fs::FileType` directly so that it can special-case
+/// FIFOs and device files, which `std::fs` has no first-class support for copying.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    Fifo,
+    Socket,
+    CharacterDevice,
+    BlockDevice,
+}
+
+impl FileType {
+    fn from_std(path: &Path, file_type: fs::FileType) -> Result<Self> {
+        if file_type.is_file() {
+            Ok(FileType::Regular)
+        } else if file_type.is_dir() {
+            Ok(FileType::Directory)
+        } else if file_type.is_symlink() {
+            Ok(FileType::Symlink)
+        } else if file_type.is_fifo() {
+            Ok(FileType::Fifo)
+        } else if file_type.is_socket() {
+            Ok(FileType::Socket)
+        } else if file_type.is_char_device() {
+            Ok(FileType::CharacterDevice)
+        } else if file_type.is_block_device() {
+            Ok(FileType::BlockDevice)
+        } else {
+            Err(Error::new(format!(
+                "{}: unrecognized file type",
+                path.display()
+            )))
+        }
+    }
+}
+
+/// Returns the [`FileType`] of `path` without following a trailing symlink.
+pub fn file_type(path: &Path) -> Result<FileType> {
+    FileType::from_std(path, symlink_metadata(path)?.file_type())
+}
+
+/// Returns the [`FileType`] of an already-read directory entry, without an extra syscall on
+/// platforms (like Linux) where the entry's `d_type` is known.
+pub fn entry_file_type(entry: &DirEntry) -> Result<FileType> {
+    let path = entry.path();
+    let file_type = entry.file_type().with_context("get file type", &path)?;
+    FileType::from_std(&path, file_type)
+}
+
+pub fn metadata(path: &Path) -> Result<Metadata> {
+    fs::metadata(path).with_context("get metadata", path)
+}
+
+pub fn symlink_metadata(path: &Path) -> Result<Metadata> {
+    fs::symlink_metadata(path).with_context("get symlink metadata", path)
+}
+
+pub fn read_link(path: &Path) -> Result<PathBuf> {
+    fs::read_link(path).with_context("read symlink", path)
+}
+
+pub fn read_dir(path: &Path) -> Result<fs::ReadDir> {
+    fs::read_dir(path).with_context("read directory", path)
+}
+
+pub fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> Result<()> {
+    let (original, link) = (original.as_ref(), link.as_ref());
+    std::os::unix::fs::symlink(original, link).with_context2("create symlink", link, original)
+}
+
+/// Copies the regular file `from` to `to`, preserving the Unix permission bits.
+///
+/// Large files on a local filesystem are copied via a pair of memory mappings instead of
+/// read/write syscalls, which cuts down on syscall overhead. This is unsound on NFS, where the
+/// file backing a mapping can change or disappear underneath us and raise `SIGBUS`, so that path
+/// is only taken once we've confirmed neither `from` nor the filesystem `to` will be created on
+/// is NFS. Other network filesystems are not detected.
+pub fn copy(from: &Path, to: &Path) -> Result<u64> {
+    let source_metadata = symlink_metadata(from)?;
+    let len = source_metadata.len();
+    // `to` doesn't exist yet (we're about to create it), so we can't `statfs` it directly;
+    // its parent directory is on the same filesystem `to` will be created on.
+    let dest_parent = match to.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if len >= MMAP_THRESHOLD && !is_network_fs(from)? && !is_network_fs(dest_parent)? {
+        copy_mmap(from, to, &source_metadata)?;
+        Ok(len)
+    } else {
+        fs::copy(from, to).with_context2("copy file", from, to)
+    }
+}
+
+fn is_network_fs(path: &Path) -> Result<bool> {
+    let path_c = path_to_cstring(path);
+    // SAFETY: `stat` is only ever written to by the `statfs` call below before being read.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path_c` is a valid, NUL-terminated C string and `stat` points at a valid,
+    // appropriately-sized buffer for `statfs` to fill in.
+    let result = unsafe { libc::statfs(path_c.as_ptr(), &mut stat) };
+    check(result, "stat filesystem", path)?;
+    // `f_type`'s width varies across libc/arch combinations; the explicit cast (rather than
+    // `i64::from`) avoids a `useless_conversion` warning on targets where it's already `i64`.
+    Ok(stat.f_type as i64 == NFS_SUPER_MAGIC)
+}
+
+fn copy_mmap(from: &Path, to: &Path, source_metadata: &Metadata) -> Result<()> {
+    let len = source_metadata.len() as usize;
+
+    let source_file = open(from)?;
+    // SAFETY: the file is mapped read-only and `copy` has already ruled out NFS, so nothing but us can invalidate the mapping for the duration of the copy.
+    let source_map = unsafe {
+        MmapOptions::new()
+            .len(len)
+            .map(&source_file)
+            .with_context("map file", from)?
+    };
+
+    let dest_file = create(to, source_metadata.permissions().mode())?;
+    // `create`'s mode is masked by the umask and ignored for an existing file.
+    dest_file
+        .set_permissions(source_metadata.permissions())
+        .with_context("set permissions", to)?;
+    dest_file
+        .set_len(len as u64)
+        .with_context("truncate file", to)?;
+    // SAFETY: `dest_file` was just truncated to exactly `len` bytes, and as above nothing else
+    // is touching it concurrently.
+    let mut dest_map = unsafe {
+        MmapOptions::new()
+            .len(len)
+            .map_mut(&dest_file)
+            .with_context("map file", to)?
+    };
+
+    dest_map
+        .par_chunks_mut(MMAP_CHUNK_SIZE)
+        .zip(source_map.par_chunks(MMAP_CHUNK_SIZE))
+        .for_each(|(dest_chunk, source_chunk)| dest_chunk.copy_from_slice(source_chunk));
+
+    Ok(())
+}
+
+/// Copies the regular file `from` to `to` via buffered reads/writes, returning `from`'s length
+/// and the hex SHA-256 digest of its bytes, computed as they stream through the copy so `from`
+/// is only read once.
+///
+/// `to` is `fsync`'d before returning, so that [`hash_file_on_disk`] can reliably re-read it
+/// from the underlying device rather than the page cache still holding the just-written pages.
+pub fn copy_with_hash(from: &Path, to: &Path) -> Result<(u64, String)> {
+    let permissions = symlink_metadata(from)?.permissions();
+    let mut source = open(from)?;
+    let mut dest = create(to, permissions.mode())?;
+    // `create`'s mode is masked by the umask and ignored for an existing file.
+    dest.set_permissions(permissions)
+        .with_context("set permissions", to)?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; HASH_BUFFER_SIZE];
+    let mut len = 0u64;
+    loop {
+        let n = source.read(&mut buffer).with_context("read file", from)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        dest.write_all(&buffer[..n]).with_context("write file", to)?;
+        len += n as u64;
+    }
+    dest.sync_all().with_context("sync file", to)?;
+
+    Ok((len, to_hex(&hasher.finalize())))
+}
+
+/// Returns the hex SHA-256 digest of `path`'s contents, streaming it through a fixed-size
+/// buffer so arbitrarily large files don't need to fit in memory.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut file = open(path)?;
+    hash_reader(&mut file, path)
+}
+
+/// Like [`hash_file`], but first drops `path`'s cached pages so the read that follows is
+/// actually served from the underlying device.
+///
+/// `--verify` exists to catch corruption introduced between the in-memory write and the bytes
+/// landing on disk; hashing straight out of the page cache (still warm from the write fcp just
+/// performed) would compare the write buffer against itself and never notice such corruption.
+pub fn hash_file_on_disk(path: &Path) -> Result<String> {
+    let mut file = open(path)?;
+    drop_page_cache(&file, path)?;
+    hash_reader(&mut file, path)
+}
+
+fn hash_reader(file: &mut File, path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; HASH_BUFFER_SIZE];
+    loop {
+        let n = file.read(&mut buffer).with_context("read file", path)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Evicts `path`'s clean cached pages so a subsequent read is served from the underlying device
+/// rather than the page cache.
+fn drop_page_cache(file: &File, path: &Path) -> Result<()> {
+    let len = file.metadata().with_context("get metadata", path)?.len();
+    // SAFETY: `file` is a valid, open file descriptor for the duration of this call.
+    let result = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            0,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+    if result != 0 {
+        return Err(Error::new(format!(
+            "couldn't drop cached pages for '{}'; ({})",
+            path.display(),
+            io::Error::from_raw_os_error(result)
+        )));
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context("open file", path)
+}
+
+pub fn create(path: &Path, mode: u32) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .with_context("create file", path)
+}
+
+pub fn create_dir(path: &Path, mode: u32) -> Result<()> {
+    fs::create_dir(path).with_context("create directory", path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode)).with_context("set permissions", path)
+}
+
+pub fn mkfifo(path: &Path, permissions: Permissions) -> Result<()> {
+    let path_c = path_to_cstring(path);
+    // SAFETY: `path_c` is a valid, NUL-terminated C string for the lifetime of this call.
+    let result = unsafe { libc::mkfifo(path_c.as_ptr(), permissions.mode()) };
+    check(result, "create fifo", path)
+}
+
+/// Which metadata, beyond the Unix permission bits (which every `copy_*` helper above already
+/// preserves unconditionally), to copy from a source onto its destination.
+#[derive(Clone, Copy, Default)]
+pub struct Preserve {
+    /// Extended attributes. On Linux this also covers POSIX ACLs, since they're exposed as the
+    /// `system.posix_acl_access`/`system.posix_acl_default` attributes.
+    pub xattr: bool,
+    /// Access and modification timestamps, with nanosecond precision.
+    pub timestamps: bool,
+}
+
+/// Replicates whichever of `source`'s extended attributes/ACLs and timestamps `preserve`
+/// selects onto `dest`.
+///
+/// Callers that create directories must invoke this *after* copying `dest`'s children, since
+/// populating a directory bumps its mtime back to "now".
+pub fn copy_metadata(
+    source: &Path,
+    dest: &Path,
+    source_metadata: &Metadata,
+    preserve: Preserve,
+) -> Result<()> {
+    if preserve.xattr {
+        copy_xattrs(source, dest)?;
+    }
+    if preserve.timestamps {
+        copy_timestamps(dest, source_metadata)?;
+    }
+    Ok(())
+}
+
+fn copy_xattrs(source: &Path, dest: &Path) -> Result<()> {
+    let source_path = path_to_cstring(source);
+    let dest_path = path_to_cstring(dest);
+
+    // SAFETY: `source_path` is a valid, NUL-terminated C string; passing a null buffer with a
+    // size of 0 is the documented way to query the required buffer size.
+    let list_size = unsafe { libc::listxattr(source_path.as_ptr(), ptr::null_mut(), 0) };
+    let list_size = check_size(list_size, "list xattrs", source)?;
+    let mut names = vec![0u8; list_size];
+    // SAFETY: `names` is large enough to hold `list_size` bytes, as just queried above.
+    let list_size = unsafe {
+        libc::listxattr(
+            source_path.as_ptr(),
+            names.as_mut_ptr().cast(),
+            names.len(),
+        )
+    };
+    let list_size = check_size(list_size, "list xattrs", source)?;
+    names.truncate(list_size);
+
+    for name in names.split_inclusive(|&byte| byte == 0) {
+        let name = CStr::from_bytes_with_nul(name)
+            .map_err(|_| Error::new(format!("{}: malformed xattr name", source.display())))?;
+
+        // SAFETY: `source_path` and `name` are valid, NUL-terminated C strings.
+        let value_size =
+            unsafe { libc::getxattr(source_path.as_ptr(), name.as_ptr(), ptr::null_mut(), 0) };
+        let value_size = check_size(value_size, "get xattr", source)?;
+        let mut value = vec![0u8; value_size];
+        // SAFETY: `value` is large enough to hold `value_size` bytes, as just queried above.
+        let value_size = unsafe {
+            libc::getxattr(
+                source_path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        let value_size = check_size(value_size, "get xattr", source)?;
+        value.truncate(value_size);
+
+        // SAFETY: `dest_path`, `name`, and `value` are a valid C string and byte buffer pair.
+        let result = unsafe {
+            libc::setxattr(
+                dest_path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        check(result, "set xattr", dest)?;
+    }
+
+    Ok(())
+}
+
+fn copy_timestamps(dest: &Path, source_metadata: &Metadata) -> Result<()> {
+    let dest_path = path_to_cstring(dest);
+    let times = [
+        libc::timespec {
+            tv_sec: source_metadata.atime(),
+            tv_nsec: source_metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: source_metadata.mtime(),
+            tv_nsec: source_metadata.mtime_nsec(),
+        },
+    ];
+    // SAFETY: `dest_path` is a valid, NUL-terminated C string and `times` holds exactly the two
+    // timespecs `utimensat` requires.
+    let result = unsafe { libc::utimensat(libc::AT_FDCWD, dest_path.as_ptr(), times.as_ptr(), 0) };
+    check(result, "set timestamps", dest)
+}
+
+// Paths on Unix can never contain a NUL byte, so this cannot fail in practice.
+fn path_to_cstring(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).expect("path contains a NUL byte")
+}
+
+// Converts the result of a `c_int`-returning syscall (0 on success, -1 on failure, with the
+// specific error left in `errno`) into a contextual `Result`.
+fn check(result: libc::c_int, operation: &str, path: &Path) -> Result<()> {
+    (if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    })
+    .with_context(operation, path)
+}
+
+// Like `check`, but for syscalls (e.g. `listxattr`/`getxattr`) that return a non-negative size
+// on success and -1 on failure.
+fn check_size(result: isize, operation: &str, path: &Path) -> Result<usize> {
+    (if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(io::Error::last_os_error())
+    })
+    .with_context(operation, path)
+}
@@ -14,8 +14,8 @@ use std::process;
 pub mod error;
 pub mod filesystem;
 
-use crate::error::{Error, Result};
-use crate::filesystem::{self as fs, FileType};
+use crate::error::{Error, IoResultExt, Result};
+use crate::filesystem::{self as fs, FileType, Preserve};
 
 pub fn graceful(message: impl Display) -> ! {
     println!("{}", message);
@@ -27,21 +27,81 @@ pub fn fatal(message: impl Display) -> ! {
     process::exit(1);
 }
 
+/// Flags that alter how [`fcp`] interprets its arguments and reports progress, as parsed by
+/// `main.rs` from the command line.
+#[derive(Default)]
+pub struct Options {
+    /// Print `source -> dest` as each file finishes copying. A directory is printed before its
+    /// contents are copied.
+    pub verbose: bool,
+    /// Always treat every positional argument as a source to be copied into this directory,
+    /// even if only one source is given.
+    pub target_directory: Option<PathBuf>,
+    /// Always treat the arguments as `SOURCE DESTINATION_FILE`, even if `DESTINATION_FILE`
+    /// happens to already exist as a directory.
+    pub no_target_directory: bool,
+    /// Which metadata, beyond the Unix permission bits (always preserved), to copy from each
+    /// source onto its destination.
+    pub preserve: Preserve,
+    /// After copying a regular file, re-read the destination and compare its SHA-256 digest with
+    /// the source's (hashed as it was copied), failing loudly on any mismatch.
+    pub verify: bool,
+}
+
+fn report_copy(source: &Path, dest: &Path, options: &Options) {
+    if options.verbose {
+        println!("{} -> {}", source.display(), dest.display());
+    }
+}
+
 // The boolean returned signifies whether an error occurred (`true`) or not (`false`). The purpose
 // of returning just a boolean instead of the underlying error itself is that we want to display
 // the error to the user as soon as it occurs (as this makes for a better user-experience during
 // long-running jobs) as opposed to propagating it upwards and printing all errors at the end.
 // However, at the end of the process we still need to know whether or not an error occurred at any
 // point in order to set the exit code appropriately.
-fn copy_file(source: &Path, source_type: Result<FileType>, dest: &Path) -> bool {
-    fn __copy_file(source: &Path, source_type: Result<FileType>, dest: &Path) -> Result<bool> {
+fn copy_file(
+    source: &Path,
+    source_type: Result<FileType>,
+    dest: &Path,
+    options: &Options,
+) -> bool {
+    fn __copy_file(
+        source: &Path,
+        source_type: Result<FileType>,
+        dest: &Path,
+        options: &Options,
+    ) -> Result<bool> {
         match source_type? {
             FileType::Regular => {
-                fs::copy(source, dest)?;
+                if options.verify {
+                    let (_, source_hash) = fs::copy_with_hash(source, dest)?;
+                    let dest_hash = fs::hash_file_on_disk(dest)?;
+                    if source_hash != dest_hash {
+                        return Err(Error::new(format!(
+                            "{}: verification failed after copying to '{}' (source sha256={}, dest sha256={})",
+                            source.display(),
+                            dest.display(),
+                            source_hash,
+                            dest_hash,
+                        )));
+                    }
+                } else {
+                    fs::copy(source, dest)?;
+                }
+                let metadata = fs::symlink_metadata(source)?;
+                fs::copy_metadata(source, dest, &metadata, options.preserve)?;
+            }
+            FileType::Directory => {
+                report_copy(source, dest, options);
+                return copy_directory(source, dest, options);
             }
-            FileType::Directory => return copy_directory(source, dest),
             FileType::Symlink => fs::symlink(fs::read_link(source)?, dest)?,
-            FileType::Fifo => fs::mkfifo(dest, fs::symlink_metadata(source)?.permissions())?,
+            FileType::Fifo => {
+                let metadata = fs::symlink_metadata(source)?;
+                fs::mkfifo(dest, metadata.permissions())?;
+                fs::copy_metadata(source, dest, &metadata, options.preserve)?;
+            }
             FileType::Socket => {
                 return Err(Error::new(format!(
                     "{}: sockets cannot be copied",
@@ -50,22 +110,25 @@ fn copy_file(source: &Path, source_type: Result<FileType>, dest: &Path) -> bool
             }
             FileType::CharacterDevice | FileType::BlockDevice => {
                 let metadata = fs::symlink_metadata(source)?;
-                let mut source = fs::open(source)?;
-                let mut dest = fs::create(dest, metadata.permissions().mode())?;
-                io::copy(&mut source, &mut dest)?;
+                let mut source_file = fs::open(source)?;
+                let mut dest_file = fs::create(dest, metadata.permissions().mode())?;
+                io::copy(&mut source_file, &mut dest_file).with_context2("copy device file", source, dest)?;
+                fs::copy_metadata(source, dest, &metadata, options.preserve)?;
             }
         }
+        report_copy(source, dest, options);
         Ok(false)
     }
 
-    __copy_file(source, source_type, dest).unwrap_or_else(|err| {
+    __copy_file(source, source_type, dest, options).unwrap_or_else(|err| {
         eprintln!("{}", err);
         true
     })
 }
 
-fn copy_directory(source: &Path, dest: &Path) -> Result<bool> {
-    fs::create_dir(dest, fs::symlink_metadata(source)?.permissions().mode())?;
+fn copy_directory(source: &Path, dest: &Path, options: &Options) -> Result<bool> {
+    let source_metadata = fs::symlink_metadata(source)?;
+    fs::create_dir(dest, source_metadata.permissions().mode())?;
     let (mut entries, mut has_err) = (Vec::new(), false);
     for entry in fs::read_dir(source)? {
         match entry {
@@ -77,12 +140,21 @@ fn copy_directory(source: &Path, dest: &Path) -> Result<bool> {
         }
     }
     entries.shrink_to_fit();
-    Ok(entries
+    let has_err = entries
         .into_par_iter()
         .map(|(file_name, file_type)| {
-            copy_file(&source.join(&file_name), file_type, &dest.join(&file_name))
+            copy_file(
+                &source.join(&file_name),
+                file_type,
+                &dest.join(&file_name),
+                options,
+            )
         })
-        .reduce(|| has_err, BitOr::bitor))
+        .reduce(|| has_err, BitOr::bitor);
+    // Children are copied before we preserve the directory's own timestamps, since writing
+    // their entries would otherwise bump `dest`'s mtime back to "now".
+    fs::copy_metadata(source, dest, &source_metadata, options.preserve)?;
+    Ok(has_err)
 }
 
 fn reject_self_copies(sources: &[PathBuf], dest: &Path) -> Result<()> {
@@ -183,7 +255,7 @@ fn file_names(sources: &[PathBuf]) -> Result<Vec<&OsStr>> {
 }
 
 /// Copy each file in `sources` into the directory `dest`.
-fn copy_into(sources: &[PathBuf], dest: &Path) -> bool {
+fn copy_into(sources: &[PathBuf], dest: &Path, options: &Options) -> bool {
     if let Some(err) = match fs::metadata(dest) {
         Err(err) => Some(err),
         Ok(metadata) if !metadata.is_dir() => {
@@ -199,31 +271,47 @@ fn copy_into(sources: &[PathBuf], dest: &Path) -> bool {
         .zip(file_names(sources).unwrap_or_else(|err| fatal(err)))
         .collect::<Box<_>>()
         .into_par_iter()
-        .map(|(source, file_name)| copy_file(source, fs::file_type(source), &dest.join(file_name)))
+        .map(|(source, file_name)| {
+            copy_file(source, fs::file_type(source), &dest.join(file_name), options)
+        })
         .reduce(|| false, BitOr::bitor)
 }
 
 // The `allow` here is present because clippy doesn't realize that `source` must be of
 // type `&PathBuf` in order for the call to `array::from_ref` to typecheck.
 #[allow(clippy::ptr_arg)]
-fn copy_single(source: &PathBuf, dest: &Path) -> bool {
+fn copy_single(source: &PathBuf, dest: &Path, options: &Options) -> bool {
     let source_metadata = fs::symlink_metadata(source).unwrap_or_else(|err| fatal(err));
     match (fs::metadata(dest), fs::symlink_metadata(dest)) {
-        (Ok(metadata), _) if metadata.is_dir() => copy_into(array::from_ref(source), dest),
+        (Ok(metadata), _) if metadata.is_dir() && !options.no_target_directory => {
+            copy_into(array::from_ref(source), dest, options)
+        }
         (_, Ok(metadata)) if source_metadata.ino() == metadata.ino() => fatal(format!(
             "Cannot overwrite file '{}' with itself '{}'",
             source.display(),
             dest.display()
         )),
-        _ => copy_file(source, fs::file_type(source), dest),
+        _ => copy_file(source, fs::file_type(source), dest, options),
     }
 }
 
-pub fn fcp(args: &[String]) -> bool {
-    let args: Box<_> = args.iter().map(PathBuf::from).collect();
-    match args.as_ref() {
+pub fn fcp(args: &[String], options: &Options) -> bool {
+    let paths: Box<_> = args.iter().map(PathBuf::from).collect();
+
+    if let Some(dest) = &options.target_directory {
+        return match paths.as_ref() {
+            [] => fatal("Please provide at least one argument (run 'fcp --help' for details)"),
+            paths => copy_into(paths, dest, options),
+        };
+    }
+
+    match paths.as_ref() {
         [] | [_] => fatal("Please provide at least two arguments (run 'fcp --help' for details)"),
-        [source, dest] => copy_single(source, dest),
-        [sources @ .., dest] => copy_into(sources, dest),
+        [source, dest] => copy_single(source, dest, options),
+        [.., dest] if options.no_target_directory => fatal(format!(
+            "extra operand '{}' (run 'fcp --help' for details)",
+            dest.display()
+        )),
+        [sources @ .., dest] => copy_into(sources, dest, options),
     }
 }
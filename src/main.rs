@@ -1,6 +1,8 @@
+use fcp::filesystem::Preserve;
 use fcp::graceful;
-use fcp::fcp;
+use fcp::{fatal, fcp, Options};
 use std::env;
+use std::path::PathBuf;
 use std::process;
 
 static HELP: &str = concat!(
@@ -19,19 +21,84 @@ OPTIONS:
             Output this usage information and exit.
 
     -V, --version
-            Output version information and exit."
+            Output version information and exit.
+
+    -v, --verbose
+            Print each SOURCE -> DEST as it's copied.
+
+    -t, --target-directory DIRECTORY
+            Copy every SOURCE into DIRECTORY, even if only one SOURCE is given.
+
+    -T, --no-target-directory
+            Treat DESTINATION_FILE as a normal file, even if it already exists as a directory.
+
+    --preserve[=ATTR_LIST]
+            Preserve ATTR_LIST (a comma-separated subset of 'xattr' and 'timestamps') in
+            addition to the Unix permission bits, which are always preserved. Defaults to
+            preserving everything if ATTR_LIST is omitted.
+
+    --no-preserve
+            Preserve only the Unix permission bits (the default).
+
+    --verify
+            After copying a regular file, compare source and destination SHA-256 digests and
+            fail loudly on a mismatch."
 );
 
 static VERSION: &str = env!("CARGO_PKG_VERSION");
 
+fn parse_preserve(attrs: &str) -> Preserve {
+    if attrs.is_empty() {
+        return Preserve {
+            xattr: true,
+            timestamps: true,
+        };
+    }
+    let mut preserve = Preserve::default();
+    for attr in attrs.split(',') {
+        match attr {
+            "xattr" => preserve.xattr = true,
+            "timestamps" => preserve.timestamps = true,
+            other => fatal(format!(
+                "--preserve: unrecognized attribute '{}' (run 'fcp --help' for details)",
+                other
+            )),
+        }
+    }
+    preserve
+}
+
 fn main() {
     let args = env::args().skip(1).collect::<Vec<String>>();
-    for arg in args.iter() {
+    let mut options = Options::default();
+    let mut paths = Vec::with_capacity(args.len());
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
         match arg.as_str() {
             "-h" | "--help" => graceful(HELP),
             "-V" | "--version" => graceful(VERSION),
-            _ => {}
+            "-v" | "--verbose" => options.verbose = true,
+            "-T" | "--no-target-directory" => options.no_target_directory = true,
+            "-t" | "--target-directory" => {
+                let dir = args.next().unwrap_or_else(|| {
+                    fatal("-t/--target-directory requires an argument (run 'fcp --help' for details)")
+                });
+                options.target_directory = Some(PathBuf::from(dir));
+            }
+            "--verify" => options.verify = true,
+            "--no-preserve" => options.preserve = Preserve::default(),
+            "--preserve" => options.preserve = parse_preserve(""),
+            arg if arg.starts_with("--preserve=") => {
+                options.preserve = parse_preserve(&arg["--preserve=".len()..]);
+            }
+            _ => paths.push(arg),
         }
     }
-    process::exit(fcp(&args) as i32);
+
+    if options.target_directory.is_some() && options.no_target_directory {
+        fatal("-t/--target-directory and -T/--no-target-directory cannot be combined (run 'fcp --help' for details)");
+    }
+
+    process::exit(fcp(&paths, &options) as i32);
 }